@@ -14,14 +14,19 @@
 
 //! Representation and calculation of movement within a view.
 
-use std::cmp::max;
+use std::cmp::{max, min};
 
 use selection::{Affinity, HorizPos, Selection, SelRegion};
 use view::View;
 use word_boundaries::WordCursor;
-use xi_rope::rope::{LinesMetric, Rope};
+use xi_rope::delta::{Builder as DeltaBuilder, Delta};
+use xi_rope::interval::Interval;
+use xi_rope::rope::{LinesMetric, Rope, RopeInfo};
 use xi_rope::tree::Cursor;
 
+/// An edit to the document, as produced by `transpose_lines`.
+pub type RopeDelta = Delta<RopeInfo>;
+
 /// The specification of a movement.
 #[derive(Clone, Copy)]
 pub enum Movement {
@@ -37,6 +42,9 @@ pub enum Movement {
     LeftOfLine,
     /// Move to right end of visible line.
     RightOfLine,
+    /// Move to the line's first non-blank grapheme, or to column 0 if the
+    /// caret is already there ("smart home").
+    FirstNonBlankOfLine,
     /// Move up one visible line.
     Up,
     /// Move down one visible line.
@@ -49,26 +57,266 @@ pub enum Movement {
     StartOfParagraph,
     /// Move to the end of the text line.
     EndOfParagraph,
+    /// Move to the start of the enclosing prose paragraph: back over
+    /// consecutive non-blank lines until a blank line or document start.
+    StartOfTextParagraph,
+    /// Move to the end of the enclosing prose paragraph: forward over
+    /// consecutive non-blank lines until a blank line or document end.
+    EndOfTextParagraph,
     /// Move to the start of the document.
     StartOfDocument,
     /// Move to the end of the document
     EndOfDocument,
 }
 
+/// The number of columns a tab expands to; tab stops fall on multiples of
+/// this value.
+const TAB_SIZE: usize = 4;
+
+/// Returns `true` if `c` is a double-width character (CJK ideographs,
+/// fullwidth forms, emoji, and the like), as rendered by a typical
+/// monospace terminal or editor font.
+fn is_wide_char(c: char) -> bool {
+    let cp = c as u32;
+    (cp >= 0x1100 && cp <= 0x115F)
+        || cp == 0x2329 || cp == 0x232A
+        || (cp >= 0x2E80 && cp <= 0xA4CF && cp != 0x303F)
+        || (cp >= 0xAC00 && cp <= 0xD7A3)
+        || (cp >= 0xF900 && cp <= 0xFAFF)
+        || (cp >= 0xFE30 && cp <= 0xFE6F)
+        || (cp >= 0xFF00 && cp <= 0xFF60)
+        || (cp >= 0xFFE0 && cp <= 0xFFE6)
+        || (cp >= 0x1F300 && cp <= 0x1FAFF)
+        || (cp >= 0x20000 && cp <= 0x3FFFD)
+}
+
+/// The display width of a single grapheme cluster `g`, given the visual
+/// column `col` it starts at (needed because tabs expand to the next tab
+/// stop rather than a fixed width).
+fn grapheme_width(g: &str, col: usize) -> usize {
+    if g == "\t" {
+        TAB_SIZE - (col % TAB_SIZE)
+    } else {
+        g.chars().next().map_or(1, |c| if is_wide_char(c) { 2 } else { 1 })
+    }
+}
+
+/// Returns the start and end offsets of `line`, with any trailing line
+/// ending stripped from the end.
+fn line_bounds(view: &View, text: &Rope, line: usize) -> (usize, usize) {
+    let start = view.offset_of_line(text, line);
+    let mut end = view.offset_of_line(text, line + 1);
+    if end > start {
+        let line_str = text.slice_to_cow(start..end);
+        let trimmed = line_str.trim_end_matches(|c| c == '\n' || c == '\r');
+        end = start + trimmed.len();
+    }
+    (start, end)
+}
+
+/// The display column of `target` relative to `start`, walking forward
+/// grapheme by grapheme; tabs expand to the next tab stop and wide
+/// graphemes count for two columns. `start` and `target` need not be a
+/// whole line's bounds: this is also used to find a column within a
+/// single wrapped row.
+fn col_in_range(text: &Rope, start: usize, target: usize) -> usize {
+    let mut col = 0;
+    let mut pos = start;
+    while pos < target {
+        let next = match text.next_grapheme_offset(pos) {
+            Some(next) if next <= target => next,
+            _ => break,
+        };
+        col += grapheme_width(&text.slice_to_cow(pos..next), col);
+        pos = next;
+    }
+    col
+}
+
+/// The inverse of `col_in_range`: finds the offset in `[start, end)` whose
+/// accumulated display width first meets or exceeds `target_col`, clamping
+/// to `end`.
+fn offset_in_range(text: &Rope, start: usize, end: usize, target_col: usize) -> usize {
+    let mut col = 0;
+    let mut pos = start;
+    while col < target_col && pos < end {
+        let next = match text.next_grapheme_offset(pos) {
+            Some(next) if next <= end => next,
+            _ => break,
+        };
+        col += grapheme_width(&text.slice_to_cow(pos..next), col);
+        pos = next;
+    }
+    pos
+}
+
+/// Converts an offset into a *visual* column: the sum of the display
+/// widths of the graphemes from the start of its line up to `offset`,
+/// where tabs expand to the next tab stop and wide graphemes count for
+/// two columns. This is what should be preserved across vertical motion
+/// in a view without soft wrap, as opposed to the raw grapheme count
+/// `View::offset_to_line_col` gives.
+fn offset_to_visual_col(view: &View, text: &Rope, offset: usize) -> usize {
+    let line = view.line_of_offset(text, offset);
+    let line_start = view.offset_of_line(text, line);
+    col_in_range(text, line_start, offset)
+}
+
+/// The inverse of `offset_to_visual_col`: finds the offset on `line` whose
+/// accumulated display width first meets or exceeds `visual_col`, clamping
+/// to the end of the line.
+fn visual_col_to_offset(view: &View, text: &Rope, line: usize, visual_col: usize) -> usize {
+    let (start, end) = line_bounds(view, text, line);
+    offset_in_range(text, start, end, visual_col)
+}
+
 /// Calculate a horizontal position in the view, based on the offset. Return
-/// value has the same type as `region_movement` for convenience.
+/// value has the same type as `region_movement` for convenience. In a
+/// soft-wrapped view the column is measured within the wrapped row, which
+/// is how `vertical_motion_wrapped` reads it.
 fn calc_horiz(view: &View, text: &Rope, offset: usize) -> (usize, Option<HorizPos>) {
-    let (_line, col) = view.offset_to_line_col(text, offset);
+    let col = match view.wrap_width() {
+        Some(wrap_width) => {
+            let line = view.line_of_offset(text, offset);
+            let rows = line_visual_rows(view, text, line, wrap_width);
+            let row_start = rows.iter().rev().find(|&&row_start| row_start <= offset)
+                .cloned().unwrap_or(rows[0]);
+            col_in_range(text, row_start, offset)
+        }
+        None => offset_to_visual_col(view, text, offset),
+    };
     (offset, Some(col))
 }
 
-/// Compute movement based on vertical motion by the given number of lines.
+/// Returns `true` if the text in `[start, end)` is empty or all whitespace.
+fn is_blank_line(text: &Rope, start: usize, end: usize) -> bool {
+    text.slice_to_cow(start..end).trim().is_empty()
+}
+
+/// The offset of the first non-whitespace grapheme in `[start, end)`, or
+/// `end` if the range is blank.
+fn first_non_blank_offset(text: &Rope, start: usize, end: usize) -> usize {
+    let mut pos = start;
+    while pos < end {
+        let next = match text.next_grapheme_offset(pos) {
+            Some(next) if next <= end => next,
+            _ => break,
+        };
+        if !text.slice_to_cow(pos..next).trim().is_empty() {
+            return pos;
+        }
+        pos = next;
+    }
+    end
+}
+
+/// Splits logical `line` into visual (screen) rows at `wrap_width`, a
+/// maximum display width in visual columns. Returns the start offset of
+/// each visual row; the first entry is always the line's own start offset.
+fn line_visual_rows(view: &View, text: &Rope, line: usize, wrap_width: usize) -> Vec<usize> {
+    let (start, end) = line_bounds(view, text, line);
+    let mut rows = vec![start];
+    let mut col = 0;
+    let mut pos = start;
+    let mut row_start = start;
+    while pos < end {
+        let next = match text.next_grapheme_offset(pos) {
+            Some(next) if next <= end => next,
+            _ => break,
+        };
+        let w = grapheme_width(&text.slice_to_cow(pos..next), col);
+        if col + w > wrap_width && pos != row_start {
+            rows.push(pos);
+            row_start = pos;
+            col = 0;
+        }
+        col += w;
+        pos = next;
+    }
+    rows
+}
+
+/// Vertical motion for a soft-wrapped view, moving by visual (screen) rows
+/// rather than logical lines.
+///
+/// Unlike a naive implementation that resolves an absolute visual row
+/// number by summing wrap points from the start of the document, this
+/// walks outward from the active offset one logical line at a time,
+/// consuming `line_delta` as it crosses row and line boundaries. Cost is
+/// proportional to the number of rows actually crossed (one line for
+/// `Up`/`Down`, roughly a viewport's worth for `UpPage`/`DownPage`), not
+/// to the size of the document.
+fn vertical_motion_wrapped(r: &SelRegion, view: &View, text: &Rope, line_delta: isize,
+    modify: bool, wrap_width: usize) -> (usize, Option<HorizPos>)
+{
+    let active = if modify {
+        r.end
+    } else if line_delta < 0 {
+        r.min()
+    } else {
+        r.max()
+    };
+    let mut line = view.line_of_offset(text, active);
+    let mut rows = line_visual_rows(view, text, line, wrap_width);
+    let mut row_idx = rows.iter().rposition(|&row_start| row_start <= active).unwrap_or(0);
+
+    let row_end = rows.get(row_idx + 1).cloned().unwrap_or_else(|| line_bounds(view, text, line).1);
+    let col = r.horiz.unwrap_or_else(|| col_in_range(text, rows[row_idx], min(active, row_end)));
+
+    let last_line = view.line_of_offset(text, text.len());
+    let mut remaining = line_delta;
+    while remaining != 0 {
+        if remaining < 0 {
+            if row_idx as isize + remaining >= 0 {
+                row_idx = (row_idx as isize + remaining) as usize;
+                remaining = 0;
+            } else {
+                remaining += row_idx as isize + 1;
+                if line == 0 {
+                    return (0, Some(col));
+                }
+                line -= 1;
+                rows = line_visual_rows(view, text, line, wrap_width);
+                row_idx = rows.len() - 1;
+            }
+        } else {
+            let rows_left = (rows.len() - 1 - row_idx) as isize;
+            if remaining <= rows_left {
+                row_idx = (row_idx as isize + remaining) as usize;
+                remaining = 0;
+            } else {
+                remaining -= rows_left + 1;
+                if line >= last_line {
+                    return (text.len(), Some(col));
+                }
+                line += 1;
+                rows = line_visual_rows(view, text, line, wrap_width);
+                row_idx = 0;
+            }
+        }
+    }
+
+    let new_row_start = rows[row_idx];
+    let new_row_end = rows.get(row_idx + 1).cloned().unwrap_or_else(|| line_bounds(view, text, line).1);
+    let new_offset = offset_in_range(text, new_row_start, new_row_end, col);
+    if new_offset == active {
+        calc_horiz(view, text, new_offset)
+    } else {
+        (new_offset, Some(col))
+    }
+}
+
+/// Compute movement based on vertical motion by the given number of lines
+/// (or, in a soft-wrapped view, visual rows).
 ///
 /// Note: in non-exceptional cases, this function preserves the `horiz`
 /// field of the selection region.
 fn vertical_motion(r: &SelRegion, view: &View, text: &Rope, line_delta: isize,
     modify: bool) -> (usize, Option<HorizPos>)
 {
+    if let Some(wrap_width) = view.wrap_width() {
+        return vertical_motion_wrapped(r, view, text, line_delta, modify, wrap_width);
+    }
     // The active point of the selection
     let active = if modify {
         r.end
@@ -80,7 +328,7 @@ fn vertical_motion(r: &SelRegion, view: &View, text: &Rope, line_delta: isize,
     let col = if let Some(col) = r.horiz {
         col
     } else {
-        view.offset_to_line_col(text, active).1
+        offset_to_visual_col(view, text, active)
     };
     // This code is quite careful to avoid integer overflow.
     // TODO: write tests to verify
@@ -97,7 +345,7 @@ fn vertical_motion(r: &SelRegion, view: &View, text: &Rope, line_delta: isize,
     if line > n_lines {
         return (text.len(), Some(col));
     }
-    let new_offset = view.line_col_to_offset(text, line, col);
+    let new_offset = visual_col_to_offset(view, text, line, col);
     if new_offset == active {
         calc_horiz(view, text, new_offset)
     } else {
@@ -116,40 +364,86 @@ fn scroll_height(view: &View) -> isize {
 // Note: most of these calls to calc_horiz could be eliminated (just use
 // None). That would cause the column to be calculated lazily on vertical
 // motion, rather than eagerly.
-fn region_movement(m: Movement, r: &SelRegion, view: &View, text: &Rope, modify: bool)
-    -> (usize, Option<HorizPos>)
+fn region_movement(m: Movement, r: &SelRegion, view: &View, text: &Rope, count: usize,
+    modify: bool) -> (usize, Option<HorizPos>)
 {
     match m {
         Movement::Left => {
             if r.is_caret() || modify {
-                if let Some(offset) = text.prev_grapheme_offset(r.end) {
-                    calc_horiz(view, text, offset)
+                let mut offset = r.end;
+                for _ in 0..count {
+                    match text.prev_grapheme_offset(offset) {
+                        Some(prev) => offset = prev,
+                        None => break,
+                    }
+                }
+                if offset == r.end {
+                    (offset, r.horiz)
                 } else {
-                    (0, r.horiz)
+                    calc_horiz(view, text, offset)
                 }
             } else {
-                calc_horiz(view, text, r.min())
+                let mut offset = r.min();
+                for _ in 1..count {
+                    match text.prev_grapheme_offset(offset) {
+                        Some(prev) => offset = prev,
+                        None => break,
+                    }
+                }
+                calc_horiz(view, text, offset)
             }
         }
         Movement::Right => {
             if r.is_caret() || modify {
-                if let Some(offset) = text.next_grapheme_offset(r.end) {
-                    calc_horiz(view, text, offset)
+                let mut offset = r.end;
+                for _ in 0..count {
+                    match text.next_grapheme_offset(offset) {
+                        Some(next) => offset = next,
+                        None => break,
+                    }
+                }
+                if offset == r.end {
+                    (offset, r.horiz)
                 } else {
-                    (r.end, r.horiz)
+                    calc_horiz(view, text, offset)
                 }
             } else {
-                calc_horiz(view, text, r.max())
+                let mut offset = r.max();
+                for _ in 1..count {
+                    match text.next_grapheme_offset(offset) {
+                        Some(next) => offset = next,
+                        None => break,
+                    }
+                }
+                calc_horiz(view, text, offset)
             }
         }
         Movement::LeftWord => {
             let mut word_cursor = WordCursor::new(text, r.end);
-            let offset = word_cursor.prev_boundary().unwrap_or(0);
+            let mut offset = r.end;
+            for _ in 0..count {
+                match word_cursor.prev_boundary() {
+                    Some(prev) => offset = prev,
+                    None => {
+                        offset = 0;
+                        break;
+                    }
+                }
+            }
             calc_horiz(view, text, offset)
         }
         Movement::RightWord => {
             let mut word_cursor = WordCursor::new(text, r.end);
-            let offset = word_cursor.next_boundary().unwrap_or_else(|| text.len());
+            let mut offset = r.end;
+            for _ in 0..count {
+                match word_cursor.next_boundary() {
+                    Some(next) => offset = next,
+                    None => {
+                        offset = text.len();
+                        break;
+                    }
+                }
+            }
             calc_horiz(view, text, offset)
         }
         Movement::LeftOfLine => {
@@ -170,8 +464,19 @@ fn region_movement(m: Movement, r: &SelRegion, view: &View, text: &Rope, modify:
             }
             calc_horiz(view, text, offset)
         }
-        Movement::Up => vertical_motion(r, view, text, -1, modify),
-        Movement::Down => vertical_motion(r, view, text, 1, modify),
+        Movement::FirstNonBlankOfLine => {
+            let line = view.line_of_offset(text, r.end);
+            let (start, end) = line_bounds(view, text, line);
+            let first_non_blank = first_non_blank_offset(text, start, end);
+            let offset = if r.end == first_non_blank && r.end != start {
+                start
+            } else {
+                first_non_blank
+            };
+            calc_horiz(view, text, offset)
+        }
+        Movement::Up => vertical_motion(r, view, text, -(count as isize), modify),
+        Movement::Down => vertical_motion(r, view, text, count as isize, modify),
         Movement::StartOfParagraph => {
             // Note: TextEdit would start at modify ? r.end : r.min()
             let mut cursor = Cursor::new(&text, r.end);
@@ -191,8 +496,70 @@ fn region_movement(m: Movement, r: &SelRegion, view: &View, text: &Rope, modify:
             }
             calc_horiz(view, text, offset)
         }
-        Movement::UpPage => vertical_motion(r, view, text, -scroll_height(view), modify),
-        Movement::DownPage => vertical_motion(r, view, text, scroll_height(view), modify),
+        Movement::StartOfTextParagraph => {
+            let mut cur_start = {
+                let mut cursor = Cursor::new(&text, r.end);
+                cursor.prev::<LinesMetric>().unwrap_or(0)
+            };
+            loop {
+                let line_end = {
+                    let mut cursor = Cursor::new(&text, cur_start);
+                    cursor.next::<LinesMetric>().unwrap_or_else(|| text.len())
+                };
+                if is_blank_line(text, cur_start, line_end) {
+                    break;
+                }
+                let mut cursor = Cursor::new(&text, cur_start);
+                match cursor.prev::<LinesMetric>() {
+                    Some(prev_start) => {
+                        let prev_end = cur_start;
+                        if is_blank_line(text, prev_start, prev_end) {
+                            break;
+                        }
+                        cur_start = prev_start;
+                    }
+                    None => break,
+                }
+            }
+            calc_horiz(view, text, cur_start)
+        }
+        Movement::EndOfTextParagraph => {
+            let mut cur_start = {
+                let line = view.line_of_offset(text, r.end);
+                view.offset_of_line(text, line)
+            };
+            let mut line_end = {
+                let mut cursor = Cursor::new(&text, cur_start);
+                cursor.next::<LinesMetric>().unwrap_or_else(|| text.len())
+            };
+            loop {
+                if is_blank_line(text, cur_start, line_end) || line_end >= text.len() {
+                    break;
+                }
+                let next_start = line_end;
+                let next_end = {
+                    let mut cursor = Cursor::new(&text, next_start);
+                    cursor.next::<LinesMetric>().unwrap_or_else(|| text.len())
+                };
+                if is_blank_line(text, next_start, next_end) {
+                    break;
+                }
+                cur_start = next_start;
+                line_end = next_end;
+            }
+            let offset = if line_end < text.len() {
+                text.prev_grapheme_offset(line_end).unwrap_or(line_end)
+            } else {
+                line_end
+            };
+            calc_horiz(view, text, offset)
+        }
+        Movement::UpPage => {
+            vertical_motion(r, view, text, -scroll_height(view) * count as isize, modify)
+        }
+        Movement::DownPage => {
+            vertical_motion(r, view, text, scroll_height(view) * count as isize, modify)
+        }
         Movement::StartOfDocument => calc_horiz(view, text, 0),
         Movement::EndOfDocument => calc_horiz(view, text, text.len()),
     }
@@ -203,14 +570,17 @@ fn region_movement(m: Movement, r: &SelRegion, view: &View, text: &Rope, modify:
 /// In a multi-region selection, this function applies the movement to each
 /// region in the selection, and returns the union of the results.
 ///
+/// `count` is the number of times the movement is applied, so that e.g.
+/// a `count` of 10 with `Movement::Down` moves down 10 lines in one call.
+///
 /// If `modify` is `true`, the selections are modified, otherwise the results
 /// of individual region movements become carets.
 pub fn selection_movement(m: Movement, s: &Selection, view: &View, text: &Rope,
-    modify: bool) -> Selection
+    count: usize, modify: bool) -> Selection
 {
     let mut result = Selection::new();
     for r in s.iter() {
-        let (offset, horiz) = region_movement(m, r, view, text, modify);
+        let (offset, horiz) = region_movement(m, r, view, text, count, modify);
         let new_region = SelRegion {
             start: if modify { r.start } else { offset },
             end: offset,
@@ -221,3 +591,348 @@ pub fn selection_movement(m: Movement, s: &Selection, view: &View, text: &Rope,
     }
     result
 }
+
+/// The index of the last line that holds real content. A document ending
+/// in a trailing newline has one further, zero-length "phantom" line at
+/// `text.len()` (the standard xi-rope convention), which doesn't correspond
+/// to anything a user would want to treat as a line to move; this returns
+/// `last_line` itself unless it is such a phantom line.
+fn last_content_line(view: &View, text: &Rope, last_line: usize) -> usize {
+    if last_line > 0 && view.offset_of_line(text, last_line) == text.len() {
+        last_line - 1
+    } else {
+        last_line
+    }
+}
+
+/// Merges the line ranges touched by each selection region into a sorted,
+/// non-overlapping list of `(start_line, end_line)` blocks (inclusive),
+/// so that regions spanning the same or adjacent lines are moved together
+/// instead of duplicating or dropping lines. Lines are clamped to
+/// `real_last_line` so that a region sitting on the trailing phantom line
+/// is treated as sitting on the last line with actual content.
+fn coalesce_line_blocks(s: &Selection, view: &View, text: &Rope, real_last_line: usize)
+    -> Vec<(usize, usize)>
+{
+    let mut blocks: Vec<(usize, usize)> = s.iter().map(|r| {
+        let start_line = view.line_of_offset(text, r.min());
+        let mut end_line = view.line_of_offset(text, r.max());
+        // Don't pull in an extra, untouched line when the region ends
+        // exactly at that line's start (e.g. a caret, or a whole-line
+        // selection ending right before the next line).
+        if end_line > start_line && view.offset_of_line(text, end_line) == r.max() {
+            end_line -= 1;
+        }
+        (min(start_line, real_last_line), min(end_line, real_last_line))
+    }).collect();
+    blocks.sort();
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in blocks.drain(..) {
+        match merged.last_mut() {
+            Some(&mut (_, ref mut last_end)) if start <= *last_end + 1 => {
+                *last_end = max(*last_end, end);
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Transposes the line block under each selection region with the line
+/// immediately above (`move_down == false`) or below (`move_down == true`),
+/// carrying the selection with the moved text.
+///
+/// Returns the edit to apply to `text`, together with the selection that
+/// should replace `s` once the edit has been applied.
+pub fn transpose_lines(s: &Selection, view: &View, text: &Rope, move_down: bool)
+    -> (RopeDelta, Selection)
+{
+    let last_line = view.line_of_offset(text, text.len());
+    let real_last_line = last_content_line(view, text, last_line);
+    let blocks = coalesce_line_blocks(s, view, text, real_last_line);
+    let mut builder = DeltaBuilder::new(text.len());
+    // Per merged block: its original `(start, end)` range, how far (and
+    // which way) its contents shifted, and the `(start, end)` range the
+    // block's own content now occupies. The last is needed because a
+    // block's trailing newline can be *deleted* (folded into a neighbor's
+    // new EOL) rather than relocated, so an offset that sat exactly on
+    // that newline doesn't land at `offset + shift` like the rest of the
+    // block — it has to clamp to wherever the block's content now ends.
+    let mut shifts: Vec<((usize, usize), isize, (usize, usize))> = Vec::new();
+
+    for (start_line, end_line) in blocks {
+        if move_down {
+            if end_line >= real_last_line {
+                continue; // the block already includes the last content line
+            }
+            let neighbor_line = end_line + 1;
+            let block_start = view.offset_of_line(text, start_line);
+            let block_end = view.offset_of_line(text, neighbor_line);
+            let neighbor_end = if neighbor_line < last_line {
+                view.offset_of_line(text, neighbor_line + 1)
+            } else {
+                text.len()
+            };
+            let block_text = text.slice_to_cow(block_start..block_end).into_owned();
+            let neighbor_text = text.slice_to_cow(block_end..neighbor_end).into_owned();
+            let new_text = if neighbor_line == last_line && !neighbor_text.ends_with('\n') {
+                // The neighbor is the document's last line and has no EOL;
+                // it is about to stop being last, so it needs one, and the
+                // block (which did have one) becomes last and loses it.
+                format!("{}\n{}", neighbor_text, &block_text[..block_text.len() - 1])
+            } else {
+                format!("{}{}", neighbor_text, block_text)
+            };
+            builder.replace(Interval::new(block_start, neighbor_end),
+                Rope::from(new_text));
+            let eol_stripped = neighbor_line == last_line && !neighbor_text.ends_with('\n');
+            let shift = (neighbor_end - block_end) as isize + if eol_stripped { 1 } else { 0 };
+            // The block moves to just past the relocated neighbor; its own
+            // content (minus a stripped trailing newline, if any) ends
+            // there.
+            let new_block_start = (block_start as isize + shift) as usize;
+            let new_block_content_len = if eol_stripped {
+                block_text.len() - 1
+            } else {
+                block_text.len()
+            };
+            shifts.push(((block_start, block_end), shift,
+                (new_block_start, new_block_start + new_block_content_len)));
+        } else {
+            if start_line == 0 {
+                continue; // the block already includes the first line
+            }
+            let neighbor_line = start_line - 1;
+            let neighbor_start = view.offset_of_line(text, neighbor_line);
+            let block_start = view.offset_of_line(text, start_line);
+            let block_end = if end_line < last_line {
+                view.offset_of_line(text, end_line + 1)
+            } else {
+                text.len()
+            };
+            let neighbor_text = text.slice_to_cow(neighbor_start..block_start).into_owned();
+            let block_text = text.slice_to_cow(block_start..block_end).into_owned();
+            let new_text = if end_line == last_line && !block_text.ends_with('\n') {
+                // The block is the document's last line and has no EOL; it
+                // is about to stop being last, so it needs one, and the
+                // neighbor (which did have one) becomes last and loses it.
+                format!("{}\n{}", block_text, &neighbor_text[..neighbor_text.len() - 1])
+            } else {
+                format!("{}{}", block_text, neighbor_text)
+            };
+            builder.replace(Interval::new(neighbor_start, block_end),
+                Rope::from(new_text));
+            let eol_added = end_line == last_line && !block_text.ends_with('\n');
+            let shift = -((block_start - neighbor_start) as isize);
+            // The block moves to the neighbor's old start; it gains a
+            // trailing newline here if it didn't already have one.
+            let new_block_start = (block_start as isize + shift) as usize;
+            let new_block_content_len = if eol_added {
+                block_text.len() + 1
+            } else {
+                block_text.len()
+            };
+            shifts.push(((block_start, block_end), shift,
+                (new_block_start, new_block_start + new_block_content_len)));
+        }
+    }
+
+    let delta = builder.build();
+    let mut result = Selection::new();
+    for r in s.iter() {
+        let block = shifts.iter()
+            .find(|&&((start, end), _, _)| r.min() >= start && r.max() <= end);
+        let (new_start, new_end) = match block {
+            Some(&(_, shift, (new_block_start, new_block_end))) => {
+                let map = |offset: usize| {
+                    max(min((offset as isize + shift) as usize, new_block_end), new_block_start)
+                };
+                (map(r.start), map(r.end))
+            }
+            None => (r.start, r.end),
+        };
+        result.add_region(SelRegion {
+            start: new_start,
+            end: new_end,
+            horiz: None,
+            affinity: r.affinity,
+        });
+    }
+    (delta, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use view::{BufferId, ViewId};
+
+    fn make_view() -> View {
+        View::new(ViewId(0), BufferId(0))
+    }
+
+    fn caret(offset: usize) -> SelRegion {
+        SelRegion { start: offset, end: offset, horiz: None, affinity: Affinity::default() }
+    }
+
+    // chunk0-1: repeat count
+
+    #[test]
+    fn left_count_clamps_at_document_start() {
+        let text = Rope::from("abcdef");
+        let view = make_view();
+        let (offset, _) = region_movement(Movement::Left, &caret(2), &view, &text, 100, false);
+        assert_eq!(offset, 0);
+    }
+
+    #[test]
+    fn right_count_clamps_at_document_end() {
+        let text = Rope::from("abcdef");
+        let view = make_view();
+        let (offset, _) = region_movement(Movement::Right, &caret(2), &view, &text, 100, false);
+        assert_eq!(offset, text.len());
+    }
+
+    #[test]
+    fn count_zero_is_noop() {
+        let text = Rope::from("abcdef");
+        let view = make_view();
+        let r = SelRegion { start: 2, end: 2, horiz: Some(2), affinity: Affinity::default() };
+        let (offset, horiz) = region_movement(Movement::Left, &r, &view, &text, 0, false);
+        assert_eq!(offset, 2);
+        assert_eq!(horiz, Some(2));
+    }
+
+    // chunk0-2: visual column, tabs, wide chars
+
+    #[test]
+    fn tab_width_rounds_up_to_next_stop() {
+        assert_eq!(grapheme_width("\t", 0), 4);
+        assert_eq!(grapheme_width("\t", 1), 3);
+        assert_eq!(grapheme_width("\t", 4), 4);
+    }
+
+    #[test]
+    fn wide_char_counts_as_two_columns() {
+        assert_eq!(grapheme_width("中", 0), 2);
+        assert_eq!(grapheme_width("a", 0), 1);
+    }
+
+    #[test]
+    fn visual_col_accounts_for_tabs_and_wide_chars() {
+        let text = Rope::from("\t中a");
+        let view = make_view();
+        assert_eq!(offset_to_visual_col(&view, &text, text.len()), 7); // 4 + 2 + 1
+    }
+
+    // chunk0-3: soft-wrap collapses to the logical-line path when disabled
+
+    #[test]
+    fn down_moves_to_next_logical_line_without_wrap() {
+        let text = Rope::from("ab\ncd\nef");
+        let view = make_view();
+        assert_eq!(view.wrap_width(), None);
+        let (offset, _) = region_movement(Movement::Down, &caret(1), &view, &text, 1, false);
+        assert_eq!(offset, 4);
+    }
+
+    #[test]
+    fn down_moves_within_then_across_wrapped_rows() {
+        // Wrapped at 4 columns, "abcdefgh" splits into visual rows "abcd"
+        // and "efgh"; the second logical line "ij" is short enough to stay
+        // on one row.
+        let text = Rope::from("abcdefgh\nij");
+        let mut view = make_view();
+        view.set_wrap_width(Some(4));
+
+        // From column 1 of the first visual row, Down should land on the
+        // same column of the second visual row of the *same* logical line.
+        let (offset, _) = region_movement(Movement::Down, &caret(1), &view, &text, 1, false);
+        assert_eq!(offset, 5);
+
+        // From column 1 of that second visual row, Down should cross into
+        // the next logical line.
+        let (offset, _) = region_movement(Movement::Down, &caret(5), &view, &text, 1, false);
+        assert_eq!(offset, 10);
+    }
+
+    // chunk0-4: line transposition, especially the trailing-EOL edge cases
+
+    #[test]
+    fn transpose_down_is_noop_for_last_content_line_with_trailing_newline() {
+        let text = Rope::from("a\nb\nc\n");
+        let view = make_view();
+        let mut s = Selection::new();
+        s.add_region(caret(4)); // caret on "c"
+        let (delta, new_sel) = transpose_lines(&s, &view, &text, true);
+        let new_text = delta.apply(&text).to_string();
+        assert_eq!(new_text, "a\nb\nc\n");
+        assert_eq!(new_sel.iter().next().unwrap().start, 4);
+    }
+
+    #[test]
+    fn transpose_up_from_trailing_phantom_line_moves_last_content_line() {
+        let text = Rope::from("a\nb\n");
+        let view = make_view();
+        let mut s = Selection::new();
+        s.add_region(caret(4)); // caret on the phantom line after the trailing newline
+        let (delta, _) = transpose_lines(&s, &view, &text, false);
+        let new_text = delta.apply(&text).to_string();
+        assert_eq!(new_text, "b\na\n");
+    }
+
+    #[test]
+    fn transpose_down_clamps_selection_spanning_stripped_newline() {
+        // "b\n" (offsets 2..4) is the whole moved block, and moving it past
+        // "c" (the document's last, newline-less line) strips that block's
+        // own trailing newline rather than relocating it. A selection
+        // spanning the whole block, not just a caret, must not have its end
+        // mapped past the new end of the document.
+        let text = Rope::from("a\nb\nc");
+        let view = make_view();
+        let mut s = Selection::new();
+        s.add_region(SelRegion { start: 2, end: 4, horiz: None, affinity: Affinity::default() });
+        let (delta, new_sel) = transpose_lines(&s, &view, &text, true);
+        let new_text = delta.apply(&text).to_string();
+        assert_eq!(new_text, "a\nc\nb");
+        let r = new_sel.iter().next().unwrap();
+        assert_eq!((r.start, r.end), (4, 5));
+    }
+
+    #[test]
+    fn transpose_down_swaps_adjacent_lines() {
+        let text = Rope::from("a\nb\nc");
+        let view = make_view();
+        let mut s = Selection::new();
+        s.add_region(caret(0)); // caret on "a"
+        let (delta, _) = transpose_lines(&s, &view, &text, true);
+        let new_text = delta.apply(&text).to_string();
+        assert_eq!(new_text, "b\na\nc");
+    }
+
+    // chunk0-5: FirstNonBlankOfLine / smart home, and text-paragraph motions
+
+    #[test]
+    fn first_non_blank_of_line_toggles_with_smart_home() {
+        let text = Rope::from("   abc");
+        let view = make_view();
+        let (offset, _) = region_movement(
+            Movement::FirstNonBlankOfLine, &caret(0), &view, &text, 1, false);
+        assert_eq!(offset, 3);
+        let (offset, _) = region_movement(
+            Movement::FirstNonBlankOfLine, &caret(3), &view, &text, 1, false);
+        assert_eq!(offset, 0);
+    }
+
+    #[test]
+    fn text_paragraph_motions_span_multiple_lines() {
+        let text = Rope::from("line one\nline two\n\nnext para\n");
+        let view = make_view();
+        let r = caret(12); // inside "line two"
+        let (start_offset, _) = region_movement(
+            Movement::StartOfTextParagraph, &r, &view, &text, 1, false);
+        assert_eq!(start_offset, 0);
+        let (end_offset, _) = region_movement(
+            Movement::EndOfTextParagraph, &r, &view, &text, 1, false);
+        assert_eq!(end_offset, 17);
+    }
+}